@@ -1,33 +1,402 @@
+use std::collections::VecDeque;
 use std::sync::Mutex;
-use tauri::Manager;
-use tauri_plugin_shell::process::CommandChild;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tauri::{AppHandle, Emitter, Manager};
+use tauri_plugin_shell::process::{CommandChild, CommandEvent, TerminatedPayload};
 use tauri_plugin_shell::ShellExt;
 
-// Store the backend process so we can kill it on exit
-struct BackendState(Mutex<Option<CommandChild>>);
+/// Initial delay before the first restart attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// Backoff is doubled on each consecutive failure, up to this cap.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// If the sidecar stays alive this long, the attempt counter resets.
+const ALIVE_RESET: Duration = Duration::from_secs(60);
+/// Give up restarting after this many consecutive rapid failures.
+const MAX_CONSECUTIVE_FAILURES: u32 = 6;
+/// How long to wait for the backend to exit on its own after SIGTERM before
+/// escalating to a forced kill.
+const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+/// How many of the most recent stderr lines to keep for crash reports.
+const RECENT_STDERR_LINES: usize = 20;
 
-#[cfg_attr(mobile, tauri::mobile_entry_point)]
-pub fn run() {
-    tauri::Builder::default()
-        .plugin(tauri_plugin_shell::init())
-        .manage(BackendState(Mutex::new(None)))
-        .setup(|app| {
-            // Start the bundled backend sidecar
-            let sidecar = app.shell().sidecar("cvprd-backend").unwrap();
+/// Tracks the backend sidecar's child process and restart bookkeeping.
+struct BackendSupervisor {
+    child: Option<CommandChild>,
+    /// Set while the app is tearing down so the supervisor stops restarting it.
+    shutting_down: bool,
+    /// Consecutive failures since the sidecar last stayed alive for `ALIVE_RESET`.
+    attempt: u32,
+    /// Ring buffer of the last `RECENT_STDERR_LINES` stderr lines, for crash reports.
+    recent_stderr: VecDeque<String>,
+    /// Bumped every time a new `supervise_backend` task is spawned, so a stale task
+    /// that's still draining its old event stream can tell it's been superseded and
+    /// stop touching shared state instead of clobbering the new task's child/attempts.
+    generation: u64,
+}
+
+impl BackendSupervisor {
+    fn new() -> Self {
+        Self {
+            child: None,
+            shutting_down: false,
+            attempt: 0,
+            recent_stderr: VecDeque::new(),
+            generation: 0,
+        }
+    }
+}
+
+/// `.1` is notified whenever the current-generation sidecar's `Terminated` event is
+/// observed, so `graceful_stop` can learn the process actually exited without polling
+/// its PID (which can be recycled once the OS reaps the zombie).
+struct BackendState(Mutex<BackendSupervisor>, tokio::sync::Notify);
+
+/// Payload emitted to the frontend for each line of backend output.
+#[derive(Clone, Serialize)]
+struct BackendLogPayload {
+    stream: &'static str,
+    line: String,
+}
 
-            match sidecar.spawn() {
-                Ok((_rx, child)) => {
-                    println!("Backend sidecar started successfully (PID: {})", child.pid());
-                    // Store the child process handle
-                    let state = app.state::<BackendState>();
-                    *state.0.lock().unwrap() = Some(child);
+/// Payload emitted when the backend sidecar has crashed.
+#[derive(Clone, Serialize)]
+struct BackendCrashedPayload {
+    attempt: u32,
+    /// True once we've given up restarting after too many rapid failures.
+    fatal: bool,
+}
+
+/// Spawns the backend sidecar and keeps it running, restarting it with
+/// exponential backoff if it terminates unexpectedly.
+///
+/// Claims a new generation up front, so a prior supervisor task that's still
+/// draining its old event stream (e.g. right after `restart_backend` kills its
+/// sidecar and this one is spawned before that task notices) can recognize it's
+/// been superseded and bail out instead of clobbering this task's state.
+fn supervise_backend(app: AppHandle) {
+    let generation = {
+        let state = app.state::<BackendState>();
+        let mut guard = state.0.lock().unwrap();
+        guard.generation += 1;
+        guard.generation
+    };
+
+    tauri::async_runtime::spawn(async move {
+        loop {
+            {
+                let state = app.state::<BackendState>();
+                if state.0.lock().unwrap().generation != generation {
+                    // A newer supervisor has taken over; this task is done.
+                    return;
+                }
+            }
+
+            let sidecar = match app.shell().sidecar("cvprd-backend") {
+                Ok(sidecar) => sidecar,
+                Err(e) => {
+                    eprintln!("Failed to resolve backend sidecar: {}", e);
+                    return;
                 }
+            };
+
+            let (mut rx, child) = match sidecar.spawn() {
+                Ok(pair) => pair,
                 Err(e) => {
                     eprintln!("Failed to start backend sidecar: {}", e);
-                    eprintln!("The application may not function correctly without the backend.");
+                    if !backoff_and_continue(&app).await {
+                        return;
+                    }
+                    continue;
+                }
+            };
+
+            println!("Backend sidecar started successfully (PID: {})", child.pid());
+            let started_at = Instant::now();
+            {
+                let state = app.state::<BackendState>();
+                let mut guard = state.0.lock().unwrap();
+                if guard.generation != generation {
+                    // Superseded while we were spawning; don't adopt this child into
+                    // state, just kill the process we just started.
+                    drop(guard);
+                    let _ = child.kill();
+                    return;
+                }
+                guard.child = Some(child);
+                // Drop any stderr left over from a previous incarnation so a crash
+                // report only ever reflects this process's own output.
+                guard.recent_stderr.clear();
+            }
+
+            // Forward the sidecar's stdout/stderr to the webview until it exits.
+            let mut terminated: Option<TerminatedPayload> = None;
+            while let Some(event) = rx.recv().await {
+                match event {
+                    CommandEvent::Stdout(bytes) => {
+                        let line = String::from_utf8_lossy(&bytes).to_string();
+                        log::info!("[backend] {}", line);
+                        let _ = app.emit("backend-log", BackendLogPayload { stream: "stdout", line });
+                    }
+                    CommandEvent::Stderr(bytes) => {
+                        let line = String::from_utf8_lossy(&bytes).to_string();
+                        log::warn!("[backend] {}", line);
+                        {
+                            let state = app.state::<BackendState>();
+                            let mut guard = state.0.lock().unwrap();
+                            guard.recent_stderr.push_back(line.clone());
+                            if guard.recent_stderr.len() > RECENT_STDERR_LINES {
+                                guard.recent_stderr.pop_front();
+                            }
+                        }
+                        let _ = app.emit("backend-log", BackendLogPayload { stream: "stderr", line });
+                    }
+                    CommandEvent::Terminated(payload) => {
+                        log::warn!("[backend] terminated: {:?}", payload);
+                        let _ = app.emit(
+                            "backend-log",
+                            BackendLogPayload {
+                                stream: "stderr",
+                                line: format!("backend exited: {:?}", payload),
+                            },
+                        );
+                        terminated = Some(payload);
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+
+            {
+                let state = app.state::<BackendState>();
+                let mut guard = state.0.lock().unwrap();
+                if guard.generation != generation {
+                    // Superseded while we were draining this sidecar's events; the
+                    // new supervisor owns `child`/`attempt` now, so leave them alone.
+                    return;
+                }
+                guard.child = None;
+                let shutting_down = guard.shutting_down;
+                let recent_stderr: Vec<String> = guard.recent_stderr.iter().cloned().collect();
+                drop(guard);
+                // Wake up a `graceful_stop` that's waiting on this exact sidecar to exit.
+                state.1.notify_waiters();
+
+                if !shutting_down {
+                    if let Some(payload) = &terminated {
+                        if payload.code != Some(0) || payload.signal.is_some() {
+                            report_backend_crash(payload, &recent_stderr);
+                        }
+                    }
+                }
+
+                if shutting_down {
+                    return;
                 }
             }
 
+            if started_at.elapsed() > ALIVE_RESET {
+                let state = app.state::<BackendState>();
+                state.0.lock().unwrap().attempt = 0;
+            }
+
+            if !backoff_and_continue(&app).await {
+                return;
+            }
+        }
+    });
+}
+
+/// Sends a crash report for an abnormal backend exit to Sentry, if the
+/// `sentry` feature is enabled and a client was initialized.
+#[cfg(feature = "sentry")]
+fn report_backend_crash(payload: &TerminatedPayload, recent_stderr: &[String]) {
+    sentry::with_scope(
+        |scope| {
+            scope.set_extra("exit_code", serde_json::json!(payload.code));
+            scope.set_extra("signal", serde_json::json!(payload.signal));
+            scope.set_extra("recent_stderr", serde_json::json!(recent_stderr.join("\n")));
+        },
+        || {
+            sentry::capture_message("Backend sidecar terminated abnormally", sentry::Level::Error);
+        },
+    );
+}
+
+#[cfg(not(feature = "sentry"))]
+fn report_backend_crash(_payload: &TerminatedPayload, _recent_stderr: &[String]) {}
+
+/// Initializes the Sentry client from a DSN in the `SENTRY_DSN` environment
+/// variable. Only compiled with the `sentry` feature; returns `None` (and
+/// leaves crash reporting disabled) if no DSN is configured. The returned guard must
+/// be kept alive (e.g. via `.manage`) for the app's lifetime.
+#[cfg(feature = "sentry")]
+fn init_sentry() -> Option<sentry::ClientInitGuard> {
+    let dsn = std::env::var("SENTRY_DSN").ok()?;
+    Some(sentry::init((
+        dsn,
+        sentry::ClientOptions {
+            release: sentry::release_name!(),
+            ..Default::default()
+        },
+    )))
+}
+
+/// Bumps the failure counter, emits `backend-crashed`, and sleeps for the
+/// backoff period. Returns `false` once the caller should give up restarting.
+async fn backoff_and_continue(app: &AppHandle) -> bool {
+    let attempt = {
+        let state = app.state::<BackendState>();
+        let mut guard = state.0.lock().unwrap();
+        guard.attempt += 1;
+        guard.attempt
+    };
+
+    let fatal = attempt >= MAX_CONSECUTIVE_FAILURES;
+    let _ = app.emit("backend-crashed", BackendCrashedPayload { attempt, fatal });
+    if fatal {
+        eprintln!("Backend sidecar failed {} times in a row, giving up", attempt);
+        return false;
+    }
+
+    let backoff = INITIAL_BACKOFF
+        .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+        .min(MAX_BACKOFF);
+    println!("Restarting backend sidecar in {:?} (attempt {})", backoff, attempt);
+    tokio::time::sleep(backoff).await;
+    true
+}
+
+/// Starts the backend sidecar, if it isn't already running.
+#[tauri::command]
+fn start_backend(app: AppHandle, state: tauri::State<BackendState>) -> Result<(), String> {
+    {
+        let mut guard = state.0.lock().map_err(|e| e.to_string())?;
+        if guard.child.is_some() {
+            return Err("backend is already running".into());
+        }
+        guard.shutting_down = false;
+        guard.attempt = 0;
+    }
+    supervise_backend(app);
+    Ok(())
+}
+
+/// Asks the backend to shut down cleanly (SIGTERM on Unix), waits up to
+/// `grace_period` for it to exit on its own, and only then force-kills it.
+/// Marks the supervisor as shutting down so it won't restart the process.
+///
+/// On Unix this sends SIGTERM and waits for the supervisor task to report (via
+/// `BackendState`'s `Notify`) that it observed the sidecar's own `Terminated` event —
+/// not by polling the child's PID, which the OS can recycle for an unrelated process
+/// once the exited child is reaped. On other platforms there is no equivalent
+/// termination signal available through the shell plugin, so this degrades to an
+/// immediate forced kill.
+async fn graceful_stop(app: &AppHandle, grace_period: Duration) -> Result<(), String> {
+    let child = {
+        let state = app.state::<BackendState>();
+        let mut guard = state.0.lock().map_err(|e| e.to_string())?;
+        guard.shutting_down = true;
+        guard.child.take()
+    };
+    let Some(child) = child else {
+        return Err("backend is not running".into());
+    };
+
+    #[cfg(unix)]
+    {
+        // Register as a waiter before signaling, so a sidecar that exits immediately
+        // can't notify before we start listening.
+        let state = app.state::<BackendState>();
+        let exited = state.1.notified();
+
+        let pid = child.pid() as libc::pid_t;
+        println!("Sending SIGTERM to backend sidecar (PID: {})", pid);
+        unsafe {
+            libc::kill(pid, libc::SIGTERM);
+        }
+
+        if tokio::time::timeout(grace_period, exited).await.is_ok() {
+            println!("Backend sidecar exited gracefully");
+            return Ok(());
+        }
+        println!("Backend sidecar did not exit within the grace period, forcing kill");
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = grace_period;
+        log::warn!(
+            "Graceful SIGTERM shutdown is only implemented on Unix; forcing an immediate kill on this platform"
+        );
+    }
+
+    child.kill().map_err(|e| e.to_string())
+}
+
+/// Stops the backend sidecar, if it's running.
+#[tauri::command]
+async fn stop_backend(app: AppHandle) -> Result<(), String> {
+    graceful_stop(&app, DEFAULT_GRACE_PERIOD).await
+}
+
+/// Stops the backend sidecar and starts a fresh one.
+#[tauri::command]
+async fn restart_backend(app: AppHandle) -> Result<(), String> {
+    let _ = graceful_stop(&app, DEFAULT_GRACE_PERIOD).await;
+    start_backend(app.clone(), app.state::<BackendState>())
+}
+
+/// Marks the supervisor as shutting down (so it stops restarting the sidecar)
+/// and kills the backend process, if one is running. A failed kill, or a missing
+/// child when no shutdown was already under way, is logged as a warning (a
+/// missing child after an earlier shutdown only at debug level) rather than treated as fatal, since
+/// we're already on our way out. Used as a synchronous fallback when there's
+/// no opportunity to await `graceful_stop`.
+fn shutdown_backend(app: &AppHandle) {
+    let Some(state) = app.try_state::<BackendState>() else {
+        return;
+    };
+    let Ok(mut guard) = state.0.lock() else {
+        return;
+    };
+    let already_shutting_down = guard.shutting_down;
+    guard.shutting_down = true;
+    match guard.child.take() {
+        Some(child) => {
+            println!("Killing backend sidecar...");
+            if let Err(e) = child.kill() {
+                log::warn!("Failed to kill backend sidecar during shutdown: {}", e);
+            }
+        }
+        None if already_shutting_down => {
+            // Expected on a clean exit: `graceful_stop` already reaped the child via
+            // the `ExitRequested` path, and this `Exit` event is just its trailing
+            // re-emission from the programmatic `app_handle.exit(0)` call.
+            log::debug!("Backend sidecar already stopped before this exit handler ran");
+        }
+        None => log::warn!("No backend sidecar process to kill during shutdown"),
+    }
+}
+
+#[cfg_attr(mobile, tauri::mobile_entry_point)]
+pub fn run() {
+    #[cfg(feature = "sentry")]
+    let sentry_guard = init_sentry();
+
+    let builder = tauri::Builder::default()
+        .plugin(tauri_plugin_shell::init())
+        .manage(BackendState(Mutex::new(BackendSupervisor::new()), tokio::sync::Notify::new()))
+        .invoke_handler(tauri::generate_handler![start_backend, stop_backend, restart_backend]);
+
+    #[cfg(feature = "sentry")]
+    let builder = builder.manage(sentry_guard);
+
+    builder
+        .setup(|app| {
+            supervise_backend(app.handle().clone());
+
             if cfg!(debug_assertions) {
                 app.handle().plugin(
                     tauri_plugin_log::Builder::default()
@@ -37,19 +406,27 @@ pub fn run() {
             }
             Ok(())
         })
-        .on_window_event(|window, event| {
-            if let tauri::WindowEvent::Destroyed = event {
-                // Kill the backend when the window is destroyed
-                if let Some(state) = window.try_state::<BackendState>() {
-                    if let Ok(mut guard) = state.0.lock() {
-                        if let Some(child) = guard.take() {
-                            println!("Killing backend sidecar...");
-                            let _ = child.kill();
-                        }
-                    }
-                }
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app_handle, event| match event {
+            // `code.is_none()` means this is a "real" exit request (last window closed,
+            // tray quit, etc). A `Some(_)` code means `AppHandle::exit()` re-emitted this
+            // event for a programmatic exit we already drained the backend for below, so
+            // let it fall through rather than preventing it again (that would loop forever).
+            tauri::RunEvent::ExitRequested { api, code, .. } if code.is_none() => {
+                // Delay the exit so the backend gets a chance to shut down cleanly
+                // before the process tears down.
+                api.prevent_exit();
+                let app_handle = app_handle.clone();
+                tauri::async_runtime::spawn(async move {
+                    let _ = graceful_stop(&app_handle, DEFAULT_GRACE_PERIOD).await;
+                    app_handle.exit(0);
+                });
             }
-        })
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+            tauri::RunEvent::Exit => {
+                // Safety net for shutdown paths that don't go through ExitRequested.
+                shutdown_backend(app_handle);
+            }
+            _ => {}
+        });
 }